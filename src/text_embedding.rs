@@ -10,32 +10,291 @@ use anyhow::Result;
 #[cfg(feature = "online")]
 use hf_hub::{
     api::sync::{ApiBuilder, ApiRepo},
-    Cache,
+    Cache, Repo, RepoType,
 };
 use ndarray::{s, Array, Dimension};
 use ort::{ExecutionProviderDispatch, GraphOptimizationLevel, Session, Value};
 use rayon::{
-    iter::{FromParallelIterator, ParallelIterator},
+    iter::{
+        FromParallelIterator, IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator,
+    },
     slice::ParallelSlice,
 };
+use sha2::{Digest, Sha256};
 use std::{
+    collections::HashMap,
     fmt::Display,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
     thread::available_parallelism,
 };
+use tokenizers::Encoding;
 
 const DEFAULT_BATCH_SIZE: usize = 256;
 const DEFAULT_MAX_LENGTH: usize = 512;
 const DEFAULT_EMBEDDING_MODEL: EmbeddingModel = EmbeddingModel::BGESmallENV15;
 
+/// Process-wide shared ONNX Runtime thread pool, committed lazily the first
+/// time a [`TextEmbedding`] instance opts in via
+/// [`InitOptions::use_shared_global_thread_pool`].
+///
+/// Alongside the commit result, this records the `intra_threads`/
+/// `inter_threads` it was committed with, so that a later caller requesting
+/// different settings (which can't retroactively change an already-running
+/// pool) can be warned that its request was silently ignored. See
+/// [`TextEmbedding::ensure_global_thread_pool`].
+static GLOBAL_THREAD_POOL: OnceLock<(Option<usize>, Option<usize>, Result<()>)> = OnceLock::new();
+
+/// A pluggable cache for previously computed embeddings, keyed by a
+/// content-addressed digest of the input text and the model configuration
+/// that produced it.
+///
+/// Implementations must be safe to share across threads, as the cache is
+/// held by [`TextEmbedding`], which is shared across worker threads while
+/// batches are run in parallel.
+pub trait EmbeddingCache: Send + Sync + std::fmt::Debug {
+    /// Look up a previously cached embedding by its content-addressed key.
+    fn get(&self, key: &str) -> Option<Embedding>;
+    /// Store an embedding under its content-addressed key.
+    fn put(&self, key: String, embedding: Embedding);
+}
+
+/// Default [`EmbeddingCache`], backed by an in-memory [`HashMap`].
+///
+/// This cache only lives as long as the [`TextEmbedding`] instance (or
+/// instances) it is attached to; nothing is persisted to disk.
+#[derive(Debug, Default)]
+pub struct InMemoryEmbeddingCache {
+    entries: Mutex<HashMap<String, Embedding>>,
+}
+
+impl EmbeddingCache for InMemoryEmbeddingCache {
+    fn get(&self, key: &str) -> Option<Embedding> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    fn put(&self, key: String, embedding: Embedding) {
+        self.entries.lock().unwrap().insert(key, embedding);
+    }
+}
+
+/// Resolve `keys` against `cache`, returning already-cached results alongside
+/// the deduplicated groups of indices that still need to be computed.
+///
+/// `miss_key_order` lists each distinct missing key exactly once, in first-seen
+/// order; `miss_groups` maps each such key to every index in `keys` that
+/// shares it, so a single computed embedding can be fanned back out to all of
+/// its duplicate positions without recomputing it.
+fn partition_cache_hits<'k>(
+    keys: &'k [String],
+    cache: &dyn EmbeddingCache,
+) -> (
+    Vec<Option<Embedding>>,
+    Vec<usize>,
+    HashMap<&'k str, Vec<usize>>,
+) {
+    let mut results: Vec<Option<Embedding>> = vec![None; keys.len()];
+    let mut miss_key_order: Vec<usize> = Vec::new();
+    let mut miss_groups: HashMap<&str, Vec<usize>> = HashMap::new();
+
+    for (index, key) in keys.iter().enumerate() {
+        if let Some(embedding) = cache.get(key) {
+            results[index] = Some(embedding);
+            continue;
+        }
+        miss_groups
+            .entry(key.as_str())
+            .or_insert_with(|| {
+                miss_key_order.push(index);
+                Vec::new()
+            })
+            .push(index);
+    }
+
+    (results, miss_key_order, miss_groups)
+}
+
+/// Greedily pack `(index, token_length)` pairs into batches bounded by
+/// `batch.len() * longest_in_batch <= token_budget`, returning each batch as
+/// a `Vec` of the original indices it contains.
+///
+/// `lengths` is sorted ascending by length before packing, so the last index
+/// added to a batch is always its longest, making `current.len() * length` an
+/// exact bound on that batch's padded size. A single length exceeding
+/// `token_budget` on its own still becomes a singleton batch.
+fn pack_by_token_budget(mut lengths: Vec<(usize, usize)>, token_budget: usize) -> Vec<Vec<usize>> {
+    lengths.sort_by_key(|&(_, length)| length);
+
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut current: Vec<usize> = Vec::new();
+    for (index, length) in lengths {
+        let prospective_size = (current.len() + 1) * length.max(1);
+        if !current.is_empty() && prospective_size > token_budget {
+            groups.push(std::mem::take(&mut current));
+        }
+        current.push(index);
+    }
+    if !current.is_empty() {
+        groups.push(current);
+    }
+    groups
+}
+
+/// Scatter `flat_embeddings` (the concatenated, in-order outputs of running
+/// each of `groups` as a batch) back into a `Vec` of length `total`, indexed
+/// by each embedding's original position instead of its batch position.
+fn scatter_by_groups(
+    groups: Vec<Vec<usize>>,
+    flat_embeddings: Vec<Embedding>,
+    total: usize,
+) -> Vec<Embedding> {
+    let mut ordered: Vec<Option<Embedding>> = vec![None; total];
+    for (index, embedding) in groups.into_iter().flatten().zip(flat_embeddings) {
+        ordered[index] = Some(embedding);
+    }
+
+    ordered
+        .into_iter()
+        .map(|embedding| embedding.expect("every input index is assigned exactly one batch"))
+        .collect()
+}
+
+/// Expand a single batch's result into one entry per input: `len` `Ok`s
+/// unpacked from `result` on success, or `len` copies of the same `Err` on
+/// failure, so that one bad batch only ever affects its own inputs.
+fn flatten_batch_result(len: usize, result: Result<Vec<Embedding>>) -> Vec<Result<Embedding>> {
+    match result {
+        Ok(embeddings) => embeddings.into_iter().map(Ok).collect(),
+        Err(err) => (0..len)
+            .map(|_| Err(anyhow::anyhow!("batch failed: {err}")))
+            .collect(),
+    }
+}
+
+/// Resilient counterpart to [`scatter_by_groups`]: scatter each group's
+/// `Result<Vec<Embedding>>` back into a `Vec<Result<Embedding>>` of length
+/// `total`, indexed by original position, isolating a failed group's error to
+/// just the indices it covers.
+fn scatter_results_by_groups(
+    groups: Vec<Vec<usize>>,
+    group_results: Vec<Result<Vec<Embedding>>>,
+    total: usize,
+) -> Vec<Result<Embedding>> {
+    let mut ordered: Vec<Option<Result<Embedding>>> = (0..total).map(|_| None).collect();
+    for (group, result) in groups.into_iter().zip(group_results) {
+        let flattened = flatten_batch_result(group.len(), result);
+        for (index, item) in group.into_iter().zip(flattened) {
+            ordered[index] = Some(item);
+        }
+    }
+
+    ordered
+        .into_iter()
+        .map(|item| item.expect("every input index is assigned exactly one batch"))
+        .collect()
+}
+
 /// Options for initializing the TextEmbedding model
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct InitOptions {
     pub model_name: EmbeddingModel,
     pub execution_providers: Vec<ExecutionProviderDispatch>,
     pub max_length: usize,
     pub cache_dir: PathBuf,
     pub show_download_progress: bool,
+    /// The specific model revision (branch, tag, or commit hash) to pull from the
+    /// Hugging Face Hub. Defaults to `None`, which resolves to the repo's `main`
+    /// branch.
+    ///
+    /// Pinning a revision protects against a crate build silently picking up
+    /// changed weights if the upstream repo is updated.
+    pub revision: Option<String>,
+    /// Optional maximum token budget per batch, used for dynamic,
+    /// length-homogeneous batching that minimizes padding waste.
+    ///
+    /// When set, [`TextEmbedding::embed`] tokenizes all inputs up front,
+    /// sorts them by token length, and greedily packs them into batches
+    /// bounded by `batch.len() * longest_in_batch <= token_budget`, instead
+    /// of using fixed-size chunks of `batch_size`. Defaults to `None`, which
+    /// keeps the fixed-size batching path.
+    pub token_budget: Option<usize>,
+    /// Optional content-addressed cache of previously computed embeddings.
+    ///
+    /// When set, [`TextEmbedding::embed`] deduplicates identical texts
+    /// within a single call, and skips tokenization and the session
+    /// entirely for inputs already present in the cache. Defaults to `None`,
+    /// which always recomputes every input. See [`InMemoryEmbeddingCache`]
+    /// for a ready-made in-memory implementation.
+    pub cache: Option<Arc<dyn EmbeddingCache>>,
+    /// Number of intra-op threads for the ONNX Runtime session. Defaults to
+    /// `None`, which uses [`available_parallelism`] for a session with its
+    /// own thread pool, or leaves the shared pool's intra-op threads at the
+    /// ONNX Runtime default.
+    ///
+    /// When `use_shared_global_thread_pool` is `true`, this only has an
+    /// effect for the first [`TextEmbedding`] instance in the process to
+    /// commit the shared pool; later instances' `intra_threads` are ignored
+    /// in favour of whatever that first instance requested (a warning is
+    /// printed to stderr if the requested value differs from the committed
+    /// one). See [`TextEmbedding::ensure_global_thread_pool`].
+    pub intra_threads: Option<usize>,
+    /// Number of inter-op threads for the ONNX Runtime session. Defaults to
+    /// `None`, which leaves inter-op parallelism at the ONNX Runtime default.
+    ///
+    /// Subject to the same first-caller-wins semantics as `intra_threads`
+    /// when `use_shared_global_thread_pool` is `true`.
+    pub inter_threads: Option<usize>,
+    /// Graph optimization level applied to the session. Defaults to
+    /// [`GraphOptimizationLevel::Level3`].
+    pub optimization_level: GraphOptimizationLevel,
+    /// Attach this session to a process-wide shared ONNX Runtime thread pool
+    /// instead of spinning up a fresh one of its own.
+    ///
+    /// Serving many models in one process with a dedicated thread pool per
+    /// session oversubscribes CPUs; enabling this lets multiple
+    /// [`TextEmbedding`] instances in the same process share a single,
+    /// right-sized pool. The pool is committed lazily from the *first*
+    /// instance to opt in; its `intra_threads`/`inter_threads` win for the
+    /// lifetime of the process, and later instances' requests are ignored
+    /// (with a stderr warning if they diverge). Defaults to `false`.
+    pub use_shared_global_thread_pool: bool,
+    /// Whether to L2-normalize pooled embeddings. Defaults to `true`, which
+    /// is what most `sentence-transformers` style callers want for cosine
+    /// similarity. Some downstream vector stores want the raw magnitudes
+    /// instead, e.g. for dot-product scoring.
+    pub normalize: bool,
+    /// Force a specific [`Pooling`] strategy, overriding the model's own
+    /// baked-in pooling config (if any). Defaults to `None`, which keeps the
+    /// model's own config (or its hardcoded default, if it ships without
+    /// one).
+    pub pooling_override: Option<Pooling>,
+}
+
+impl std::fmt::Debug for InitOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InitOptions")
+            .field("model_name", &self.model_name)
+            .field("execution_providers", &self.execution_providers)
+            .field("max_length", &self.max_length)
+            .field("cache_dir", &self.cache_dir)
+            .field("show_download_progress", &self.show_download_progress)
+            .field("revision", &self.revision)
+            .field("token_budget", &self.token_budget)
+            .field(
+                "cache",
+                &self.cache.as_ref().map(|_| "Arc<dyn EmbeddingCache>"),
+            )
+            .field("intra_threads", &self.intra_threads)
+            .field("inter_threads", &self.inter_threads)
+            .field("optimization_level", &self.optimization_level)
+            .field(
+                "use_shared_global_thread_pool",
+                &self.use_shared_global_thread_pool,
+            )
+            .field("normalize", &self.normalize)
+            .field("pooling_override", &self.pooling_override)
+            .finish()
+    }
 }
 
 impl Default for InitOptions {
@@ -46,6 +305,15 @@ impl Default for InitOptions {
             max_length: DEFAULT_MAX_LENGTH,
             cache_dir: Path::new(DEFAULT_CACHE_DIR).to_path_buf(),
             show_download_progress: true,
+            revision: None,
+            token_budget: None,
+            cache: None,
+            intra_threads: None,
+            inter_threads: None,
+            optimization_level: GraphOptimizationLevel::Level3,
+            use_shared_global_thread_pool: false,
+            normalize: true,
+            pooling_override: None,
         }
     }
 }
@@ -53,10 +321,49 @@ impl Default for InitOptions {
 /// Options for initializing UserDefinedEmbeddingModel
 ///
 /// Model files are held by the UserDefinedEmbeddingModel struct
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct InitOptionsUserDefined {
     pub execution_providers: Vec<ExecutionProviderDispatch>,
     pub max_length: usize,
+    /// See [`InitOptions::token_budget`].
+    pub token_budget: Option<usize>,
+    /// See [`InitOptions::cache`].
+    pub cache: Option<Arc<dyn EmbeddingCache>>,
+    /// See [`InitOptions::intra_threads`].
+    pub intra_threads: Option<usize>,
+    /// See [`InitOptions::inter_threads`].
+    pub inter_threads: Option<usize>,
+    /// See [`InitOptions::optimization_level`].
+    pub optimization_level: GraphOptimizationLevel,
+    /// See [`InitOptions::use_shared_global_thread_pool`].
+    pub use_shared_global_thread_pool: bool,
+    /// See [`InitOptions::normalize`].
+    pub normalize: bool,
+    /// See [`InitOptions::pooling_override`].
+    pub pooling_override: Option<Pooling>,
+}
+
+impl std::fmt::Debug for InitOptionsUserDefined {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InitOptionsUserDefined")
+            .field("execution_providers", &self.execution_providers)
+            .field("max_length", &self.max_length)
+            .field("token_budget", &self.token_budget)
+            .field(
+                "cache",
+                &self.cache.as_ref().map(|_| "Arc<dyn EmbeddingCache>"),
+            )
+            .field("intra_threads", &self.intra_threads)
+            .field("inter_threads", &self.inter_threads)
+            .field("optimization_level", &self.optimization_level)
+            .field(
+                "use_shared_global_thread_pool",
+                &self.use_shared_global_thread_pool,
+            )
+            .field("normalize", &self.normalize)
+            .field("pooling_override", &self.pooling_override)
+            .finish()
+    }
 }
 
 impl Default for InitOptionsUserDefined {
@@ -64,6 +371,14 @@ impl Default for InitOptionsUserDefined {
         Self {
             execution_providers: Default::default(),
             max_length: DEFAULT_MAX_LENGTH,
+            token_budget: None,
+            cache: None,
+            intra_threads: None,
+            inter_threads: None,
+            optimization_level: GraphOptimizationLevel::Level3,
+            use_shared_global_thread_pool: false,
+            normalize: true,
+            pooling_override: None,
         }
     }
 }
@@ -76,6 +391,14 @@ impl From<InitOptions> for InitOptionsUserDefined {
         InitOptionsUserDefined {
             execution_providers: options.execution_providers,
             max_length: options.max_length,
+            token_budget: options.token_budget,
+            cache: options.cache,
+            intra_threads: options.intra_threads,
+            inter_threads: options.inter_threads,
+            optimization_level: options.optimization_level,
+            use_shared_global_thread_pool: options.use_shared_global_thread_pool,
+            normalize: options.normalize,
+            pooling_override: options.pooling_override,
         }
     }
 }
@@ -107,12 +430,28 @@ pub mod output {
 
     /// Generates thea default array transformer for the [`TextEmbedding`] model using the
     /// provided output precedence.
+    ///
+    /// `pooling` selects how token-level outputs are reduced to a single
+    /// vector per input; when `None`, the model's own baked-in pooling
+    /// config (if any) is used. `normalize_output` controls whether the
+    /// pooled vector is L2-normalized, which most `sentence-transformers`
+    /// style callers want for cosine similarity, but some downstream vector
+    /// stores want the raw magnitudes for dot-product scoring instead.
     #[allow(unused_variables)]
     pub fn transformer_with_precedence(
         output_precedence: impl OutputPrecedence,
         pooling: Option<Pooling>,
+        normalize_output: bool,
     ) -> impl Fn(&[SingleBatchOutput]) -> anyhow::Result<Vec<Embedding>> {
         move |batches| {
+            let post_process = |row: &[f32]| -> Embedding {
+                if normalize_output {
+                    normalize(row)
+                } else {
+                    row.to_vec()
+                }
+            };
+
             // Not using `par_iter` here: the operations here is probably not
             // computationally expensive enough to warrant spinning up costs of the threads.
             batches
@@ -125,14 +464,14 @@ pub mod output {
                             2 => Ok(array
                                 .rows()
                                 .into_iter()
-                                .map(|row| normalize(row.as_slice().unwrap()))
+                                .map(|row| post_process(row.as_slice().unwrap()))
                                 .collect::<Vec<Embedding>>()),
                             // 3D tensor - `Qdrant`, `BERT` models etc
                             3 => Ok(array
                                 .slice(s![.., 0, ..])
                                 .rows()
                                 .into_iter()
-                                .map(|row| normalize(row.as_slice().unwrap()))
+                                .map(|row| post_process(row.as_slice().unwrap()))
                                 .collect::<Vec<Embedding>>()),
                             _ => Err(anyhow::Error::msg(format!(
                                 "Invalid output shape: {shape:?}. Expected 2D or 3D tensor.",
@@ -154,6 +493,17 @@ pub struct TextEmbedding {
     pub pooling: Option<Pooling>,
     session: Session,
     need_token_type_ids: bool,
+    token_budget: Option<usize>,
+    cache: Option<Arc<dyn EmbeddingCache>>,
+    /// Prefix mixed into every cache key, derived from the model identity
+    /// (including the pinned `revision`, if any), `max_length`, pooling mode
+    /// and normalization flag, so that a cache shared across
+    /// differently-configured instances can't return a hit for the wrong
+    /// configuration.
+    cache_key_prefix: String,
+    /// Whether pooled embeddings are L2-normalized. See
+    /// [`InitOptions::normalize`].
+    normalize: bool,
 }
 
 impl Display for EmbeddingModel {
@@ -169,9 +519,10 @@ impl Display for EmbeddingModel {
 impl TextEmbedding {
     /// Try to generate a new TextEmbedding Instance
     ///
-    /// Uses the highest level of Graph optimization
-    ///
-    /// Uses the total number of CPUs available as the number of intra-threads
+    /// Uses [`GraphOptimizationLevel::Level3`] by default, and the total
+    /// number of CPUs available as the number of intra-threads, unless
+    /// overridden via [`InitOptions::optimization_level`],
+    /// [`InitOptions::intra_threads`] and [`InitOptions::inter_threads`].
     #[cfg(feature = "online")]
     pub fn try_new(options: InitOptions) -> Result<Self> {
         let InitOptions {
@@ -180,14 +531,22 @@ impl TextEmbedding {
             max_length,
             cache_dir,
             show_download_progress,
+            revision,
+            token_budget,
+            cache,
+            intra_threads,
+            inter_threads,
+            optimization_level,
+            use_shared_global_thread_pool,
+            normalize,
+            pooling_override,
         } = options;
 
-        let threads = available_parallelism()?.get();
-
         let model_repo = TextEmbedding::retrieve_model(
             model_name.clone(),
             cache_dir.clone(),
             show_download_progress,
+            revision.clone(),
         )?;
 
         let model_file_name = TextEmbedding::get_model_info(&model_name).model_file;
@@ -204,17 +563,42 @@ impl TextEmbedding {
         }
 
         // prioritise loading pooling config if available, if not (thanks qdrant!), look for it in hardcoded
-        let post_processing = model_name.get_default_pooling_method();
+        // unless the caller forces a specific pooling strategy via `pooling_override`.
+        let post_processing = pooling_override.or_else(|| model_name.get_default_pooling_method());
 
-        let session = Session::builder()?
+        let session_builder = Session::builder()?
             .with_execution_providers(execution_providers)?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(threads)?
-            .commit_from_file(model_file_reference)?;
+            .with_optimization_level(optimization_level)?;
+        let session_builder = TextEmbedding::configure_session_threads(
+            session_builder,
+            intra_threads,
+            inter_threads,
+            use_shared_global_thread_pool,
+        )?;
+        let session = session_builder.commit_from_file(model_file_reference)?;
 
         let tokenizer = load_tokenizer_hf_hub(model_repo, max_length)?;
         dbg!((&model_name, &post_processing));
-        Ok(Self::new(tokenizer, session, post_processing))
+
+        // Fold the pinned revision into the cache key, so that two instances
+        // built from the same `model_name` but different `revision`s (the
+        // whole point of pinning a revision) never alias each other's cache
+        // entries.
+        let model_key = match &revision {
+            Some(revision) => format!("{model_name}@{revision}"),
+            None => model_name.to_string(),
+        };
+
+        Ok(Self::new(
+            tokenizer,
+            session,
+            post_processing,
+            token_budget,
+            cache,
+            model_key,
+            max_length,
+            normalize,
+        ))
     }
 
     /// Create a TextEmbedding instance from model files provided by the user.
@@ -227,40 +611,160 @@ impl TextEmbedding {
         let InitOptionsUserDefined {
             execution_providers,
             max_length,
+            token_budget,
+            cache,
+            intra_threads,
+            inter_threads,
+            optimization_level,
+            use_shared_global_thread_pool,
+            normalize,
+            pooling_override,
         } = options;
 
-        let threads = available_parallelism()?.get();
-
-        let session = Session::builder()?
+        let session_builder = Session::builder()?
             .with_execution_providers(execution_providers)?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(threads)?
-            .commit_from_memory(&model.onnx_file)?;
+            .with_optimization_level(optimization_level)?;
+        let session_builder = TextEmbedding::configure_session_threads(
+            session_builder,
+            intra_threads,
+            inter_threads,
+            use_shared_global_thread_pool,
+        )?;
+        let session = session_builder.commit_from_memory(&model.onnx_file)?;
 
         let tokenizer = load_tokenizer(model.tokenizer_files, max_length)?;
-        dbg!(&model.pooling);
-        Ok(Self::new(tokenizer, session, model.pooling))
+        let post_processing = pooling_override.or(model.pooling);
+        dbg!(&post_processing);
+
+        // There is no stable model name for a user-supplied model, so derive
+        // a content-based identifier from the ONNX file bytes instead, to
+        // keep cache keys unique per distinct model.
+        let model_key = format!("{:x}", Sha256::digest(&model.onnx_file));
+
+        Ok(Self::new(
+            tokenizer,
+            session,
+            post_processing,
+            token_budget,
+            cache,
+            model_key,
+            max_length,
+            normalize,
+        ))
     }
 
     /// Private method to return an instance
-    fn new(tokenizer: Tokenizer, session: Session, post_process: Option<Pooling>) -> Self {
+    fn new(
+        tokenizer: Tokenizer,
+        session: Session,
+        post_process: Option<Pooling>,
+        token_budget: Option<usize>,
+        cache: Option<Arc<dyn EmbeddingCache>>,
+        model_key: impl Display,
+        max_length: usize,
+        normalize: bool,
+    ) -> Self {
         let need_token_type_ids = session
             .inputs
             .iter()
             .any(|input| input.name == "token_type_ids");
+        let cache_key_prefix = format!("{model_key}:{max_length}:{post_process:?}:{normalize}");
         Self {
             tokenizer,
             session,
             need_token_type_ids,
             pooling: post_process,
+            token_budget,
+            cache,
+            cache_key_prefix,
+            normalize,
+        }
+    }
+
+    /// Apply intra/inter-op thread settings to a [`Session`] builder, either
+    /// by configuring it with its own dedicated thread pool, or by attaching
+    /// it to the process-wide shared pool lazily built by
+    /// [`TextEmbedding::ensure_global_thread_pool`].
+    fn configure_session_threads(
+        session_builder: ort::SessionBuilder,
+        intra_threads: Option<usize>,
+        inter_threads: Option<usize>,
+        use_shared_global_thread_pool: bool,
+    ) -> Result<ort::SessionBuilder> {
+        if use_shared_global_thread_pool {
+            TextEmbedding::ensure_global_thread_pool(intra_threads, inter_threads)?;
+            return Ok(session_builder.with_disable_per_session_threads()?);
+        }
+
+        let intra_threads = intra_threads.unwrap_or(available_parallelism()?.get());
+        let session_builder = session_builder.with_intra_threads(intra_threads)?;
+        Ok(match inter_threads {
+            Some(inter_threads) => session_builder.with_inter_threads(inter_threads)?,
+            None => session_builder,
+        })
+    }
+
+    /// Lazily commit a process-wide shared ONNX Runtime thread pool, built
+    /// once from the first caller's thread settings.
+    ///
+    /// Running many models in one process with a dedicated thread pool per
+    /// session oversubscribes CPUs; sessions that opt into the shared pool
+    /// via `use_shared_global_thread_pool` attach to this instead of
+    /// spinning up their own.
+    ///
+    /// Only the first caller's `intra_threads`/`inter_threads` are ever
+    /// actually committed, since the pool can't be reconfigured once built;
+    /// a later caller whose explicitly requested settings diverge from the
+    /// committed ones has its request silently ignored, other than a
+    /// warning printed to stderr. A later caller that passes `None` (i.e.
+    /// doesn't request anything specific) is never warned about, even if
+    /// the committed pool was built with explicit thread counts.
+    fn ensure_global_thread_pool(
+        intra_threads: Option<usize>,
+        inter_threads: Option<usize>,
+    ) -> Result<()> {
+        let (committed_intra_threads, committed_inter_threads, result) = GLOBAL_THREAD_POOL
+            .get_or_init(|| {
+                let commit = || -> Result<()> {
+                    let mut options = ort::environment::GlobalThreadPoolOptions::default();
+                    if let Some(intra_threads) = intra_threads {
+                        options = options.with_intra_threads(intra_threads)?;
+                    }
+                    if let Some(inter_threads) = inter_threads {
+                        options = options.with_inter_threads(inter_threads)?;
+                    }
+                    ort::init()
+                        .with_name("fastembed")
+                        .with_global_thread_pool(options)
+                        .commit()?;
+                    Ok(())
+                };
+                (intra_threads, inter_threads, commit())
+            });
+
+        let intra_conflicts = intra_threads.is_some() && intra_threads != *committed_intra_threads;
+        let inter_conflicts = inter_threads.is_some() && inter_threads != *committed_inter_threads;
+        if intra_conflicts || inter_conflicts {
+            eprintln!(
+                "fastembed: shared ONNX Runtime thread pool was already committed with \
+                 intra_threads={committed_intra_threads:?}, inter_threads={committed_inter_threads:?}; \
+                 ignoring this instance's request for intra_threads={intra_threads:?}, \
+                 inter_threads={inter_threads:?} (first caller wins)"
+            );
         }
+
+        result.as_ref().map(|_| ()).map_err(|err| {
+            anyhow::anyhow!("failed to initialise shared ONNX Runtime thread pool: {err}")
+        })
     }
+
     /// Return the TextEmbedding model's directory from cache or remote retrieval
     #[cfg(feature = "online")]
     fn retrieve_model(
         model: EmbeddingModel,
         cache_dir: PathBuf,
         show_download_progress: bool,
+        revision: Option<String>,
     ) -> Result<ApiRepo> {
         let cache = Cache::new(cache_dir);
         let api = ApiBuilder::from_cache(cache)
@@ -268,7 +772,14 @@ impl TextEmbedding {
             .build()
             .unwrap();
 
-        let repo = api.model(model.to_string());
+        let repo = match revision {
+            Some(revision) => api.repo(Repo::with_revision(
+                model.to_string(),
+                RepoType::Model,
+                revision,
+            )),
+            None => api.model(model.to_string()),
+        };
         Ok(repo)
     }
 
@@ -319,72 +830,128 @@ impl TextEmbedding {
         // Determine the batch size, default if not specified
         let batch_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
 
-        let batches =
-            anyhow::Result::<Vec<_>>::from_par_iter(texts.par_chunks(batch_size).map(|batch| {
-                // Encode the texts in the batch
-                let inputs = batch.iter().map(|text| text.as_ref()).collect();
-                let encodings = self.tokenizer.encode_batch(inputs, true).unwrap();
-
-                // Extract the encoding length and batch size
-                let encoding_length = encodings[0].len();
-                let batch_size = batch.len();
-
-                let max_size = encoding_length * batch_size;
-
-                // Preallocate arrays with the maximum size
-                let mut ids_array = Vec::with_capacity(max_size);
-                let mut mask_array = Vec::with_capacity(max_size);
-                let mut typeids_array = Vec::with_capacity(max_size);
-
-                // Not using par_iter because the closure needs to be FnMut
-                encodings.iter().for_each(|encoding| {
-                    let ids = encoding.get_ids();
-                    let mask = encoding.get_attention_mask();
-                    let typeids = encoding.get_type_ids();
-
-                    // Extend the preallocated arrays with the current encoding
-                    // Requires the closure to be FnMut
-                    ids_array.extend(ids.iter().map(|x| *x as i64));
-                    mask_array.extend(mask.iter().map(|x| *x as i64));
-                    typeids_array.extend(typeids.iter().map(|x| *x as i64));
-                });
+        let batches = anyhow::Result::<Vec<_>>::from_par_iter(
+            texts
+                .par_chunks(batch_size)
+                .map(|batch| self.run_batch(batch.iter().map(|text| text.as_ref()).collect())),
+        )?;
 
-                // Create CowArrays from vectors
-                let inputs_ids_array =
-                    Array::from_shape_vec((batch_size, encoding_length), ids_array)?;
+        Ok(EmbeddingOutput::new(batches))
+    }
 
-                let attention_mask_array =
-                    Array::from_shape_vec((batch_size, encoding_length), mask_array)?;
+    /// Tokenize and run a single batch of texts through the session, packaging
+    /// the result into a [`SingleBatchOutput`].
+    ///
+    /// This is the unit of work of the fixed-size batching in
+    /// [`TextEmbedding::transform`] and its resilient counterpart; the
+    /// token-budget dynamic batching calls
+    /// [`TextEmbedding::run_encoded_batch`] directly on its pre-tokenized
+    /// inputs.
+    fn run_batch<'e, 'r, 's>(&'e self, inputs: Vec<&str>) -> Result<SingleBatchOutput<'r, 's>>
+    where
+        'e: 'r,
+        'e: 's,
+    {
+        // Encode the texts in the batch
+        let encodings = self
+            .tokenizer
+            .encode_batch(inputs, true)
+            .map_err(|err| anyhow::anyhow!("tokenizer error: {err}"))?;
+        self.run_encoded_batch(encodings)
+    }
 
-                let token_type_ids_array =
-                    Array::from_shape_vec((batch_size, encoding_length), typeids_array)?;
+    /// Run a batch of already-tokenized [`Encoding`]s through the session,
+    /// packaging the result into a [`SingleBatchOutput`].
+    ///
+    /// Unlike [`TextEmbedding::run_batch`], this does not tokenize its
+    /// inputs, so callers that already hold `Encoding`s (e.g. from a prior
+    /// length-measurement pass) can reuse them instead of paying for the
+    /// tokenizer twice. Every encoding is padded up to the longest one in
+    /// `encodings`, since callers aren't required to have packed them to a
+    /// uniform length up front.
+    fn run_encoded_batch<'e, 'r, 's>(
+        &'e self,
+        mut encodings: Vec<Encoding>,
+    ) -> Result<SingleBatchOutput<'r, 's>>
+    where
+        'e: 'r,
+        'e: 's,
+    {
+        let encoding_length = encodings
+            .iter()
+            .map(|encoding| encoding.len())
+            .max()
+            .unwrap_or(0);
 
-                let mut session_inputs = ort::inputs![
-                    "input_ids" => Value::from_array(inputs_ids_array)?,
-                    "attention_mask" => Value::from_array(attention_mask_array.view())?,
-                ]?;
+        let padding = self
+            .tokenizer
+            .get_padding()
+            .cloned()
+            .expect("tokenizer must be configured with padding");
+        encodings.iter_mut().for_each(|encoding| {
+            encoding.pad(
+                encoding_length,
+                padding.pad_id,
+                padding.pad_type_id,
+                &padding.pad_token,
+                padding.direction,
+            );
+        });
 
-                if self.need_token_type_ids {
-                    session_inputs.push((
-                        "token_type_ids".into(),
-                        Value::from_array(token_type_ids_array)?.into(),
-                    ));
-                }
+        let batch_size = encodings.len();
 
-                Ok(
-                    // Package all the data required for post-processing (e.g. pooling)
-                    // into a SingleBatchOutput struct.
-                    SingleBatchOutput {
-                        session_outputs: self
-                            .session
-                            .run(session_inputs)
-                            .map_err(anyhow::Error::new)?,
-                        attention_mask_array,
-                    },
-                )
-            }))?;
+        let max_size = encoding_length * batch_size;
 
-        Ok(EmbeddingOutput::new(batches))
+        // Preallocate arrays with the maximum size
+        let mut ids_array = Vec::with_capacity(max_size);
+        let mut mask_array = Vec::with_capacity(max_size);
+        let mut typeids_array = Vec::with_capacity(max_size);
+
+        // Not using par_iter because the closure needs to be FnMut
+        encodings.iter().for_each(|encoding| {
+            let ids = encoding.get_ids();
+            let mask = encoding.get_attention_mask();
+            let typeids = encoding.get_type_ids();
+
+            // Extend the preallocated arrays with the current encoding
+            // Requires the closure to be FnMut
+            ids_array.extend(ids.iter().map(|x| *x as i64));
+            mask_array.extend(mask.iter().map(|x| *x as i64));
+            typeids_array.extend(typeids.iter().map(|x| *x as i64));
+        });
+
+        // Create CowArrays from vectors
+        let inputs_ids_array = Array::from_shape_vec((batch_size, encoding_length), ids_array)?;
+
+        let attention_mask_array =
+            Array::from_shape_vec((batch_size, encoding_length), mask_array)?;
+
+        let token_type_ids_array =
+            Array::from_shape_vec((batch_size, encoding_length), typeids_array)?;
+
+        let mut session_inputs = ort::inputs![
+            "input_ids" => Value::from_array(inputs_ids_array)?,
+            "attention_mask" => Value::from_array(attention_mask_array.view())?,
+        ]?;
+
+        if self.need_token_type_ids {
+            session_inputs.push((
+                "token_type_ids".into(),
+                Value::from_array(token_type_ids_array)?.into(),
+            ));
+        }
+
+        Ok(
+            // Package all the data required for post-processing (e.g. pooling)
+            // into a SingleBatchOutput struct.
+            SingleBatchOutput {
+                session_outputs: self
+                    .session
+                    .run(session_inputs)
+                    .map_err(anyhow::Error::new)?,
+                attention_mask_array,
+            },
+        )
     }
 
     /// Method to generate sentence embeddings for a Vec of texts.
@@ -403,11 +970,377 @@ impl TextEmbedding {
         texts: Vec<S>,
         batch_size: Option<usize>,
     ) -> Result<Vec<Embedding>> {
+        let Some(cache) = self.cache.as_deref() else {
+            return self.compute_embeddings(texts, batch_size);
+        };
+
+        // Resolve cache hits up front, and group the remaining texts by their
+        // cache key so identical strings within this call are only computed
+        // once, no matter how many times they're repeated.
+        let keys: Vec<String> = texts
+            .iter()
+            .map(|text| self.cache_key(text.as_ref()))
+            .collect();
+
+        let (mut results, miss_key_order, miss_groups) = partition_cache_hits(&keys, cache);
+
+        if !miss_key_order.is_empty() {
+            let unique_texts: Vec<&str> = miss_key_order
+                .iter()
+                .map(|&index| texts[index].as_ref())
+                .collect();
+            let unique_embeddings = self.compute_embeddings(unique_texts, batch_size)?;
+
+            for (&first_index, embedding) in miss_key_order.iter().zip(unique_embeddings) {
+                let key = &keys[first_index];
+                cache.put(key.clone(), embedding.clone());
+                for &duplicate_index in &miss_groups[key.as_str()] {
+                    results[duplicate_index] = Some(embedding.clone());
+                }
+            }
+        }
+
+        Ok(results
+            .into_iter()
+            .map(|embedding| embedding.expect("every index is resolved via cache hit or miss"))
+            .collect())
+    }
+
+    /// Content-addressed cache key for a single input text, mixing in
+    /// [`Self::cache_key_prefix`] so that embeddings from different model
+    /// configurations never collide when a cache is shared across instances.
+    fn cache_key(&self, text: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.cache_key_prefix.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(text.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Compute embeddings for `texts`, dispatching to the token-budget
+    /// dynamic batching path if one is configured, or the fixed-size
+    /// `batch_size` path otherwise.
+    ///
+    /// This is the uncached core shared by [`TextEmbedding::embed`]'s
+    /// cache-hit/miss handling.
+    fn compute_embeddings<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<Embedding>> {
+        if let Some(token_budget) = self.token_budget {
+            return self.embed_with_token_budget(texts, token_budget);
+        }
+
         let batches = self.transform(texts, batch_size)?;
 
         batches.export_with_transformer(output::transformer_with_precedence(
             output::OUTPUT_TYPE_PRECENDENCE,
             self.pooling.clone(),
+            self.normalize,
         ))
     }
+
+    /// Dynamic, length-homogeneous batching that minimizes padding waste.
+    ///
+    /// Tokenizes every text in `texts` individually (once, via
+    /// [`TextEmbedding::run_encoded_batch`] later reusing the same
+    /// `Encoding`s rather than re-tokenizing) to learn its length, sorts by
+    /// length, then greedily packs texts into batches bounded by
+    /// `batch.len() * longest_in_batch <= token_budget` (a text longer than
+    /// the budget on its own still becomes a singleton batch, truncated at
+    /// the tokenizer's configured `max_length`). Each batch is then run as
+    /// usual, and the resulting embeddings are scattered back to match the
+    /// caller's original input order. A tokenizer failure on any input fails
+    /// the whole call with that error, same as a session-run failure.
+    fn embed_with_token_budget<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        token_budget: usize,
+    ) -> Result<Vec<Embedding>> {
+        let encodings: Vec<Encoding> = texts
+            .par_iter()
+            .map(|text| {
+                self.tokenizer
+                    .encode(text.as_ref(), true)
+                    .map_err(|err| anyhow::anyhow!("tokenizer error: {err}"))
+            })
+            .collect::<Result<Vec<Encoding>>>()?;
+
+        let lengths: Vec<(usize, usize)> = encodings
+            .iter()
+            .enumerate()
+            .map(|(index, encoding)| (index, encoding.len()))
+            .collect();
+        let groups = pack_by_token_budget(lengths, token_budget);
+
+        let batches = anyhow::Result::<Vec<_>>::from_par_iter(groups.par_iter().map(|group| {
+            let group_encodings: Vec<Encoding> = group
+                .iter()
+                .map(|&index| encodings[index].clone())
+                .collect();
+            self.run_encoded_batch(group_encodings)
+        }))?;
+
+        let embeddings = EmbeddingOutput::new(batches).export_with_transformer(
+            output::transformer_with_precedence(
+                output::OUTPUT_TYPE_PRECENDENCE,
+                self.pooling.clone(),
+                self.normalize,
+            ),
+        )?;
+
+        Ok(scatter_by_groups(groups, embeddings, texts.len()))
+    }
+
+    /// Resilient variant of [`TextEmbedding::embed`] that isolates batch
+    /// failures instead of letting one bad batch abort the entire call.
+    ///
+    /// Honors [`InitOptions::cache`] (deduplicating identical texts and
+    /// serving cache hits without touching the session) and
+    /// [`InitOptions::token_budget`] (length-homogeneous dynamic batching)
+    /// exactly like [`TextEmbedding::embed`], but never aborts the whole
+    /// call on a batch error: a batch that errors contributes an `Err` for
+    /// each of its inputs instead, while every other batch's results are
+    /// unaffected. The returned `Vec` is always the same length as `texts`
+    /// and stays index-aligned with it, so a caller can quarantine and retry
+    /// only the failed inputs without losing track of which embedding
+    /// belongs to which input.
+    pub fn embed_resilient<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Vec<Result<Embedding>> {
+        let Some(cache) = self.cache.as_deref() else {
+            return self.compute_embeddings_resilient(texts, batch_size);
+        };
+
+        let keys: Vec<String> = texts
+            .iter()
+            .map(|text| self.cache_key(text.as_ref()))
+            .collect();
+
+        let (hits, miss_key_order, miss_groups) = partition_cache_hits(&keys, cache);
+        let mut results: Vec<Option<Result<Embedding>>> =
+            hits.into_iter().map(|hit| hit.map(Ok)).collect();
+
+        if !miss_key_order.is_empty() {
+            let unique_texts: Vec<&str> = miss_key_order
+                .iter()
+                .map(|&index| texts[index].as_ref())
+                .collect();
+            let unique_results = self.compute_embeddings_resilient(unique_texts, batch_size);
+
+            for (&first_index, result) in miss_key_order.iter().zip(unique_results) {
+                let key = &keys[first_index];
+                match result {
+                    Ok(embedding) => {
+                        cache.put(key.clone(), embedding.clone());
+                        for &duplicate_index in &miss_groups[key.as_str()] {
+                            results[duplicate_index] = Some(Ok(embedding.clone()));
+                        }
+                    }
+                    Err(err) => {
+                        // `err` already carries a fully-formed message (e.g.
+                        // "batch failed: ..." from `flatten_batch_result`);
+                        // fan it out to every duplicate verbatim instead of
+                        // wrapping it again, which would double the prefix.
+                        let message = err.to_string();
+                        for &duplicate_index in &miss_groups[key.as_str()] {
+                            results[duplicate_index] = Some(Err(anyhow::anyhow!(message.clone())));
+                        }
+                    }
+                }
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.expect("every index is resolved via cache hit or miss"))
+            .collect()
+    }
+
+    /// Uncached, resilient core shared by [`TextEmbedding::embed_resilient`]:
+    /// dispatches to the token-budget dynamic batching path if one is
+    /// configured, or the fixed-size `batch_size` path otherwise, isolating
+    /// failures per batch instead of aborting the whole call.
+    fn compute_embeddings_resilient<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Vec<Result<Embedding>> {
+        if let Some(token_budget) = self.token_budget {
+            return self.embed_with_token_budget_resilient(texts, token_budget);
+        }
+
+        let batch_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+
+        texts
+            .par_chunks(batch_size)
+            .map(|batch| {
+                let inputs = batch.iter().map(|text| text.as_ref()).collect();
+                let result = self.run_batch(inputs).and_then(|single_batch_output| {
+                    EmbeddingOutput::new(vec![single_batch_output]).export_with_transformer(
+                        output::transformer_with_precedence(
+                            output::OUTPUT_TYPE_PRECENDENCE,
+                            self.pooling.clone(),
+                            self.normalize,
+                        ),
+                    )
+                });
+                (batch.len(), result)
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(|(len, result)| flatten_batch_result(len, result))
+            .collect()
+    }
+
+    /// Token-budget variant of
+    /// [`TextEmbedding::compute_embeddings_resilient`], isolating failures
+    /// per packed batch while preserving the caller's original index order
+    /// exactly like [`TextEmbedding::embed_with_token_budget`].
+    ///
+    /// A tokenizer failure on one input is isolated to that input alone
+    /// (reported as its own singleton-batch `Err`), same as a session-run
+    /// failure is isolated to its own batch, so neither can abort the whole
+    /// call.
+    fn embed_with_token_budget_resilient<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        token_budget: usize,
+    ) -> Vec<Result<Embedding>> {
+        let encode_results: Vec<Result<Encoding>> = texts
+            .par_iter()
+            .map(|text| {
+                self.tokenizer
+                    .encode(text.as_ref(), true)
+                    .map_err(|err| anyhow::anyhow!("tokenizer error: {err}"))
+            })
+            .collect();
+
+        // A tokenizer failure is isolated to its own singleton "batch" up
+        // front, exactly like a session-run failure is isolated to its own
+        // batch below, instead of `unwrap`-panicking the whole call.
+        let mut encodings: HashMap<usize, Encoding> = HashMap::new();
+        let mut lengths: Vec<(usize, usize)> = Vec::new();
+        let mut failed_groups: Vec<Vec<usize>> = Vec::new();
+        let mut failed_results: Vec<Result<Vec<Embedding>>> = Vec::new();
+        for (index, encode_result) in encode_results.into_iter().enumerate() {
+            match encode_result {
+                Ok(encoding) => {
+                    lengths.push((index, encoding.len()));
+                    encodings.insert(index, encoding);
+                }
+                Err(err) => {
+                    failed_groups.push(vec![index]);
+                    failed_results.push(Err(err));
+                }
+            }
+        }
+
+        let mut groups = pack_by_token_budget(lengths, token_budget);
+        let mut group_results: Vec<Result<Vec<Embedding>>> = groups
+            .par_iter()
+            .map(|group| {
+                let group_encodings: Vec<Encoding> =
+                    group.iter().map(|index| encodings[index].clone()).collect();
+                self.run_encoded_batch(group_encodings)
+                    .and_then(|single_batch_output| {
+                        EmbeddingOutput::new(vec![single_batch_output]).export_with_transformer(
+                            output::transformer_with_precedence(
+                                output::OUTPUT_TYPE_PRECENDENCE,
+                                self.pooling.clone(),
+                                self.normalize,
+                            ),
+                        )
+                    })
+            })
+            .collect();
+
+        groups.extend(failed_groups);
+        group_results.extend(failed_results);
+
+        scatter_results_by_groups(groups, group_results, texts.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_by_token_budget_respects_boundary() {
+        // Lengths 3, 3, 3: any two fit (6 <= 6), but three don't (9 > 6).
+        let groups = pack_by_token_budget(vec![(0, 3), (1, 3), (2, 3)], 6);
+        assert_eq!(groups, vec![vec![0, 1], vec![2]]);
+    }
+
+    #[test]
+    fn pack_by_token_budget_oversized_singleton() {
+        // A single length exceeding the budget still becomes its own batch.
+        let groups = pack_by_token_budget(vec![(0, 10)], 6);
+        assert_eq!(groups, vec![vec![0]]);
+    }
+
+    #[test]
+    fn pack_by_token_budget_empty_input() {
+        assert!(pack_by_token_budget(Vec::new(), 6).is_empty());
+    }
+
+    #[test]
+    fn scatter_by_groups_restores_original_order() {
+        let groups = vec![vec![2, 0], vec![1]];
+        let flat_embeddings = vec![vec![2.0], vec![0.0], vec![1.0]];
+        let scattered = scatter_by_groups(groups, flat_embeddings, 3);
+        assert_eq!(scattered, vec![vec![0.0], vec![1.0], vec![2.0]]);
+    }
+
+    #[test]
+    fn scatter_results_by_groups_isolates_failure_to_its_own_indices() {
+        let groups = vec![vec![0, 2], vec![1]];
+        let group_results: Vec<Result<Vec<Embedding>>> = vec![
+            Ok(vec![vec![0.0], vec![2.0]]),
+            Err(anyhow::anyhow!("session error")),
+        ];
+        let scattered = scatter_results_by_groups(groups, group_results, 3);
+
+        assert_eq!(scattered[0].as_ref().unwrap(), &vec![0.0]);
+        assert_eq!(scattered[2].as_ref().unwrap(), &vec![2.0]);
+        assert!(scattered[1].is_err());
+        assert!(scattered[1]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("session error"));
+    }
+
+    #[test]
+    fn partition_cache_hits_separates_hits_from_misses() {
+        let cache = InMemoryEmbeddingCache::default();
+        cache.put("key-a".to_string(), vec![1.0]);
+
+        let keys = vec!["key-a".to_string(), "key-b".to_string()];
+        let (results, miss_key_order, miss_groups) = partition_cache_hits(&keys, &cache);
+
+        assert_eq!(results[0], Some(vec![1.0]));
+        assert_eq!(results[1], None);
+        assert_eq!(miss_key_order, vec![1]);
+        assert_eq!(miss_groups["key-b"], vec![1]);
+    }
+
+    #[test]
+    fn partition_cache_hits_fans_out_duplicate_keys() {
+        let cache = InMemoryEmbeddingCache::default();
+        let keys = vec!["dup".to_string(), "unique".to_string(), "dup".to_string()];
+        let (results, miss_key_order, miss_groups) = partition_cache_hits(&keys, &cache);
+
+        // Every index is a miss, so `results` stays all-`None` here; the
+        // caller is expected to fill it in after computing each unique key.
+        assert!(results.iter().all(Option::is_none));
+        // Each distinct key appears exactly once, in first-seen order.
+        assert_eq!(miss_key_order, vec![0, 1]);
+        assert_eq!(miss_groups["dup"], vec![0, 2]);
+        assert_eq!(miss_groups["unique"], vec![1]);
+    }
 }